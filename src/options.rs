@@ -1,13 +1,126 @@
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::sync::Arc;
+use std::fmt;
 
 use RustlsConfig;
 
+/// Errors produced while validating `MqttOptions`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptionsError {
+    /// client id was empty or started with whitespace
+    InvalidClientId,
+    /// keep alive was configured below the 5 second minimum
+    KeepAliveTooSmall(u16),
+    /// maximum packet size was zero
+    ZeroMaxPacketSize,
+    /// broker address could not be parsed as `host:port`
+    InvalidBrokerAddr(String),
+    /// subscription identifier was zero or out of the variable byte integer range
+    InvalidSubscriptionId(u32),
+    /// backoff reconnect params were invalid (`multiplier < 1.0` or `initial > max`)
+    InvalidBackoff,
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OptionsError::InvalidClientId => write!(f, "client id shouldn't be empty or start with whitespace"),
+            OptionsError::KeepAliveTooSmall(secs) => write!(f, "keep alive should be at least 5 secs, got {}", secs),
+            OptionsError::ZeroMaxPacketSize => write!(f, "max packet size should be greater than zero"),
+            OptionsError::InvalidBrokerAddr(ref addr) => write!(f, "broker address '{}' is not a valid host:port", addr),
+            OptionsError::InvalidSubscriptionId(id) => write!(f, "subscription identifier {} is not in 1..=268435455", id),
+            OptionsError::InvalidBackoff => write!(f, "backoff multiplier should be >= 1.0 and initial delay <= max delay"),
+        }
+    }
+}
+
+impl ::std::error::Error for OptionsError {}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ReconnectOptions {
     Never,
     AfterFirstSuccess(Duration),
     Always(Duration),
+    /// Exponentially growing reconnect delay: `next_delay(attempt)` returns
+    /// `min(initial * multiplier^attempt, max)`. With `jitter` the delay is
+    /// randomized within its lower half (equal jitter), so it stays in
+    /// `[delay/2, delay)` — enough to decorrelate a fleet of clients without
+    /// ever collapsing to ~0. The attempt counter lives in `ReconnectState`
+    /// and goes back to zero only when its `reset` is called.
+    Backoff {
+        initial: Duration,
+        max: Duration,
+        multiplier: f64,
+        jitter: bool,
+    },
+}
+
+impl ReconnectOptions {
+    /// Delay to wait before the reconnect attempt numbered `attempt`
+    /// (zero based). For the fixed-interval variants the attempt number is
+    /// ignored; for `Backoff` the delay grows geometrically up to `max`.
+    ///
+    /// With `jitter`, the computed delay `d` is reduced to a value in
+    /// `[d/2, d)` (equal jitter) — it keeps at least half the backoff as a
+    /// floor so reconnects never stampede, while still spreading clients out.
+    pub fn next_delay(&self, attempt: u32) -> Option<Duration> {
+        match *self {
+            ReconnectOptions::Never => None,
+            ReconnectOptions::AfterFirstSuccess(d) | ReconnectOptions::Always(d) => Some(d),
+            ReconnectOptions::Backoff { initial, max, multiplier, jitter } => {
+                let grown = initial.as_secs_f64() * multiplier.powi(attempt as i32);
+                let delay = grown.min(max.as_secs_f64());
+                let delay = if jitter {
+                    let half = delay / 2.0;
+                    half + jitter_fraction() * half
+                } else {
+                    delay
+                };
+                Some(Duration::from_secs_f64(delay))
+            }
+        }
+    }
+}
+
+/// A value in `[0, 1)` used to spread reconnect delays across clients. It is
+/// derived from the sub-second part of the wall clock rather than a real RNG
+/// (no rng crate is pulled in); this is a coarse source, so fleets whose clocks
+/// are tightly NTP-synced will be only loosely decorrelated.
+fn jitter_fraction() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    f64::from(nanos) / 1_000_000_000.0
+}
+
+/// Tracks reconnect attempts for a connection. `next_delay` returns the delay
+/// for the current attempt and advances the attempt counter; `reset` sets the
+/// counter back to zero so the backoff curve starts over. It does not observe
+/// CONNACKs itself: the counter is reset only by a call to `reset`.
+#[derive(Copy, Clone, Debug)]
+pub struct ReconnectState {
+    opts: ReconnectOptions,
+    attempt: u32,
+}
+
+impl ReconnectState {
+    pub fn new(opts: ReconnectOptions) -> ReconnectState {
+        ReconnectState { opts, attempt: 0 }
+    }
+
+    /// Delay to wait before the next reconnect attempt, advancing the attempt
+    /// counter. Returns `None` when reconnection is disabled.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        let delay = self.opts.next_delay(self.attempt);
+        self.attempt = self.attempt.saturating_add(1);
+        delay
+    }
+
+    /// Reset the attempt counter after a successful CONNACK.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
 }
 
 #[derive(Clone)]
@@ -22,6 +135,66 @@ impl TlsOptions {
     }
 }
 
+/// A validated MQTT v5 subscription identifier. Intended to tag a topic filter
+/// so a v5 broker can echo it on matching PUBLISHes, enabling `id -> handler`
+/// dispatch instead of wildcard re-matching. Valid values are `1..=268_435_455`
+/// (a variable byte integer, zero is reserved). This is the identifier type
+/// only; encoding it into SUBSCRIBE/PUBLISH and surfacing it on notifications
+/// is not yet wired up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u32);
+
+impl SubscriptionId {
+    const MAX: u32 = 268_435_455;
+
+    /// Build a subscription identifier, rejecting `0` and values that exceed
+    /// the variable byte integer range.
+    pub fn new(id: u32) -> Result<SubscriptionId, OptionsError> {
+        if id == 0 || id > SubscriptionId::MAX {
+            return Err(OptionsError::InvalidSubscriptionId(id));
+        }
+        Ok(SubscriptionId(id))
+    }
+
+    /// The raw identifier value.
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
+
+/// A topic filter and its QoS, optionally tagged with a [`SubscriptionId`].
+/// This is the value a future `subscribe` overload would accept; the codec and
+/// notification plumbing that would act on the id are not implemented here.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subscription {
+    pub topic_path: String,
+    pub qos: ::mqtt3::QoS,
+    pub id: Option<SubscriptionId>,
+}
+
+impl Subscription {
+    /// A subscription for `topic_path` at `qos` with no identifier.
+    pub fn new<S: Into<String>>(topic_path: S, qos: ::mqtt3::QoS) -> Subscription {
+        Subscription { topic_path: topic_path.into(), qos, id: None }
+    }
+
+    /// Tag this subscription with an identifier for indexed dispatch.
+    pub fn with_id(mut self, id: SubscriptionId) -> Subscription {
+        self.id = Some(id);
+        self
+    }
+}
+
+/// Transport used to reach the broker. Selecting the connection method is
+/// independent of authentication (see `set_credentials`).
+#[derive(Clone)]
+pub enum ConnectionMethod {
+    /// Plain TCP
+    Tcp,
+    /// TLS with the supplied configuration
+    Tls(TlsOptions),
+}
+
 #[derive(Clone)]
 pub struct MqttOptions {
     /// broker address that you want to connect to
@@ -40,14 +213,30 @@ pub struct MqttOptions {
     pub max_packet_size: usize,
     /// mqtt will
     pub last_will: Option<::mqtt3::LastWill>,
-    /// TLS configuration
-    pub tls: Option<TlsOptions>,
+    /// requested MQTT v5 session expiry interval in seconds
+    /// (`0` = session ends at disconnect, `u32::MAX` = never expires). Stored
+    /// for when a v5 CONNECT is emitted; the current `connect_packet` builder
+    /// is MQTT 3.1.1 and does not put it on the wire.
+    pub session_expiry_interval: Option<u32>,
+    /// session expiry interval the broker returned in CONNACK, which may be
+    /// smaller than the requested value. `None` until the first CONNACK.
+    pub negotiated_session_expiry: Option<u32>,
+    /// requested MQTT v5 will delay interval in seconds: the broker defers
+    /// publishing the last will until the client has been offline this long,
+    /// cancelling it if the client reconnects within the window. Stored for a
+    /// v5 CONNECT; not emitted by the current MQTT 3.1.1 `connect_packet`.
+    pub will_delay_interval: Option<u32>,
+    /// username and password sent in the CONNECT packet
+    pub credentials: Option<(String, String)>,
+    /// transport used to reach the broker
+    pub connection_method: ConnectionMethod,
 }
 
 impl MqttOptions {
+    /// Construct options with sane defaults. The client id and broker address
+    /// are validated by [`build`](MqttOptions::build), not here, so the
+    /// constructor stays infallible.
     pub fn new<S1: Into<String>, S2: Into<String>>(id: S1, addr: S2) -> MqttOptions {
-        // TODO: Validate client id. Shouldn't be empty or start with spaces
-        // TODO: Validate if addr is proper address type
         MqttOptions {
             broker_addr: addr.into(),
             keep_alive: Some(10),
@@ -57,17 +246,17 @@ impl MqttOptions {
             reconnect: ReconnectOptions::AfterFirstSuccess(Duration::from_secs(10)),
             max_packet_size: 100 * 1024,
             last_will: None,
-            tls: None,
+            session_expiry_interval: None,
+            negotiated_session_expiry: None,
+            will_delay_interval: None,
+            credentials: None,
+            connection_method: ConnectionMethod::Tcp,
         }
     }
 
     /// Set number of seconds after which client should ping the broker
     /// if there is no other data exchange
     pub fn set_keep_alive(mut self, secs: u16) -> Self {
-        if secs < 5 {
-            panic!("Keep alives should be greater than 5 secs");
-        }
-
         self.keep_alive = Some(secs);
         self
     }
@@ -92,23 +281,235 @@ impl MqttOptions {
     }
 
     /// Time interval after which client should retry for new
-    /// connection if there are any disconnections. By default, no retry will happen
-    pub fn set_reconnect_opts(mut self, opts: ReconnectOptions) -> Self {
+    /// connection if there are any disconnections. By default, retries happen
+    /// every 10 seconds, but only after a first successful connection
+    /// (`AfterFirstSuccess`).
+    ///
+    /// A `Backoff` is rejected with `OptionsError::InvalidBackoff` when
+    /// `multiplier < 1.0` (which would make the delay shrink over time) or
+    /// `initial > max`. `build()` re-runs the same check for callers that set
+    /// the field directly.
+    pub fn set_reconnect_opts(mut self, opts: ReconnectOptions) -> Result<Self, OptionsError> {
+        if let ReconnectOptions::Backoff { initial, max, multiplier, .. } = opts {
+            if multiplier < 1.0 || initial > max {
+                return Err(OptionsError::InvalidBackoff);
+            }
+        }
+
         self.reconnect = opts;
+        Ok(self)
+    }
+
+    /// Set username and password to be sent in the CONNECT packet
+    pub fn set_credentials<S1: Into<String>, S2: Into<String>>(mut self, user: S1, pass: S2) -> Self {
+        self.credentials = Some((user.into(), pass.into()));
         self
     }
 
-    /// Set tls option
-    /// Supports tls client cert
+    /// Set the transport used to reach the broker (plain TCP or TLS)
+    pub fn set_connection_method(mut self, method: ConnectionMethod) -> Self {
+        self.connection_method = method;
+        self
+    }
+
+    /// Backwards-compatible shim for the old `tls: Option<TlsOptions>` field.
+    /// `Some(opts)` selects `ConnectionMethod::Tls`, `None` selects plain TCP.
     pub fn set_tls_opts(mut self, opts: Option<TlsOptions>) -> Self {
-        self.tls = opts;
+        self.connection_method = match opts {
+            Some(opts) => ConnectionMethod::Tls(opts),
+            None => ConnectionMethod::Tcp,
+        };
         self
     }
 
+    /// The TLS options in use, if the connection method is `Tls`. Replaces the
+    /// old public `tls` field for callers that read it back.
+    pub fn tls(&self) -> Option<&TlsOptions> {
+        match self.connection_method {
+            ConnectionMethod::Tls(ref opts) => Some(opts),
+            ConnectionMethod::Tcp => None,
+        }
+    }
+
+    /// Build the CONNECT packet for this connection, populating the username
+    /// and password fields from the configured credentials.
+    pub fn connect_packet(&self) -> ::mqtt3::Connect {
+        let (username, password) = match self.credentials {
+            Some((ref user, ref pass)) => (Some(user.clone()), Some(pass.clone())),
+            None => (None, None),
+        };
+
+        ::mqtt3::Connect {
+            protocol: ::mqtt3::Protocol::MQTT(4),
+            keep_alive: self.keep_alive.unwrap_or(0),
+            client_id: self.client_id.clone(),
+            clean_session: self.clean_session,
+            last_will: self.last_will.clone(),
+            username,
+            password,
+        }
+    }
+
+    /// Set the MQTT v5 session expiry interval (seconds). `0` ends the session
+    /// at disconnect, `u32::MAX` keeps it forever. Stored only: the MQTT 3.1.1
+    /// `connect_packet` does not send it.
+    pub fn set_session_expiry_interval(mut self, secs: u32) -> Self {
+        self.session_expiry_interval = Some(secs);
+        self
+    }
+
+    /// Set the MQTT v5 will delay interval (seconds) after which an offline
+    /// client's last will is published by the broker. Stored only: the
+    /// MQTT 3.1.1 `connect_packet` does not send it.
+    pub fn set_will_delay_interval(mut self, secs: u32) -> Self {
+        self.will_delay_interval = Some(secs);
+        self
+    }
+
+    /// Record the session expiry interval the broker returned in CONNACK. The
+    /// negotiated value is clamped to the requested one (the broker may only
+    /// shrink it) and is what `session_expiry` returns from then on. Nothing in
+    /// these options resumes or clears local queue state based on it.
+    pub fn apply_connack_session_expiry(&mut self, negotiated: u32) {
+        let negotiated = match self.session_expiry_interval {
+            Some(requested) => negotiated.min(requested),
+            None => negotiated,
+        };
+        self.negotiated_session_expiry = Some(negotiated);
+    }
+
+    /// The effective session expiry: the broker-negotiated value once a CONNACK
+    /// has been seen, otherwise the requested value.
+    pub fn session_expiry(&self) -> Option<u32> {
+        self.negotiated_session_expiry.or(self.session_expiry_interval)
+    }
+
     /// Set MQTT last will
     /// This message will be emit by the broker on disconnect.
     pub fn set_last_will(mut self, will: Option<::mqtt3::LastWill>) -> Self {
         self.last_will = will;
         self
     }
+
+    /// Validate the accumulated configuration and hand back the finished
+    /// options. Runs every check at once so a misconfiguration surfaces as an
+    /// `OptionsError` instead of aborting the process.
+    pub fn build(self) -> Result<MqttOptions, OptionsError> {
+        if self.client_id.is_empty() || self.client_id.starts_with(char::is_whitespace) {
+            return Err(OptionsError::InvalidClientId);
+        }
+
+        if let Some(secs) = self.keep_alive {
+            if secs < 5 {
+                return Err(OptionsError::KeepAliveTooSmall(secs));
+            }
+        }
+
+        if self.max_packet_size == 0 {
+            return Err(OptionsError::ZeroMaxPacketSize);
+        }
+
+        if let ReconnectOptions::Backoff { initial, max, multiplier, .. } = self.reconnect {
+            if multiplier < 1.0 || initial > max {
+                return Err(OptionsError::InvalidBackoff);
+            }
+        }
+
+        let valid_addr = self.broker_addr
+            .rfind(':')
+            .map(|i| (&self.broker_addr[..i], &self.broker_addr[i + 1..]))
+            .map_or(false, |(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid_addr {
+            return Err(OptionsError::InvalidBrokerAddr(self.broker_addr.clone()));
+        }
+
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_rejects_empty_or_whitespace_client_id() {
+        assert_eq!(MqttOptions::new("", "localhost:1883").build().err(), Some(OptionsError::InvalidClientId));
+        assert_eq!(MqttOptions::new(" foo", "localhost:1883").build().err(), Some(OptionsError::InvalidClientId));
+        assert_eq!(MqttOptions::new("\tfoo", "localhost:1883").build().err(), Some(OptionsError::InvalidClientId));
+        assert!(MqttOptions::new("foo", "localhost:1883").build().is_ok());
+    }
+
+    #[test]
+    fn build_rejects_small_keep_alive() {
+        let opts = MqttOptions::new("foo", "localhost:1883").set_keep_alive(4);
+        assert_eq!(opts.build().err(), Some(OptionsError::KeepAliveTooSmall(4)));
+    }
+
+    #[test]
+    fn build_rejects_zero_max_packet_size() {
+        let opts = MqttOptions::new("foo", "localhost:1883").set_max_packet_size(0);
+        assert_eq!(opts.build().err(), Some(OptionsError::ZeroMaxPacketSize));
+    }
+
+    #[test]
+    fn build_validates_broker_addr() {
+        assert!(MqttOptions::new("foo", "localhost:1883").build().is_ok());
+        assert!(MqttOptions::new("foo", "localhost").build().is_err());
+        assert!(MqttOptions::new("foo", ":1883").build().is_err());
+        assert!(MqttOptions::new("foo", "localhost:noport").build().is_err());
+    }
+
+    #[test]
+    fn subscription_id_bounds() {
+        assert_eq!(SubscriptionId::new(0).err(), Some(OptionsError::InvalidSubscriptionId(0)));
+        assert_eq!(SubscriptionId::new(1).map(|id| id.get()), Ok(1));
+        assert_eq!(SubscriptionId::new(268_435_455).map(|id| id.get()), Ok(268_435_455));
+        assert!(SubscriptionId::new(268_435_456).is_err());
+    }
+
+    #[test]
+    fn connack_session_expiry_is_clamped_to_request() {
+        let mut opts = MqttOptions::new("foo", "localhost:1883").set_session_expiry_interval(60);
+        opts.apply_connack_session_expiry(30);
+        assert_eq!(opts.session_expiry(), Some(30));
+
+        let mut opts = MqttOptions::new("foo", "localhost:1883").set_session_expiry_interval(60);
+        opts.apply_connack_session_expiry(120);
+        assert_eq!(opts.session_expiry(), Some(60));
+    }
+
+    #[test]
+    fn backoff_delay_grows_and_is_capped() {
+        let opts = ReconnectOptions::Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: false,
+        };
+        assert_eq!(opts.next_delay(0), Some(Duration::from_secs(1)));
+        assert_eq!(opts.next_delay(2), Some(Duration::from_secs(4)));
+        // 1 * 2^4 = 16, capped at max = 10
+        assert_eq!(opts.next_delay(4), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn set_reconnect_opts_rejects_invalid_backoff() {
+        let opts = MqttOptions::new("foo", "localhost:1883");
+        let shrinking = ReconnectOptions::Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            multiplier: 0.5,
+            jitter: false,
+        };
+        assert_eq!(opts.set_reconnect_opts(shrinking).err(), Some(OptionsError::InvalidBackoff));
+
+        let opts = MqttOptions::new("foo", "localhost:1883");
+        let inverted = ReconnectOptions::Backoff {
+            initial: Duration::from_secs(10),
+            max: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: false,
+        };
+        assert_eq!(opts.set_reconnect_opts(inverted).err(), Some(OptionsError::InvalidBackoff));
+    }
 }